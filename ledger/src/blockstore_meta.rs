@@ -48,23 +48,228 @@ pub struct Index {
     coding: ShredIndex,
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[derive(Clone, Debug, Default)]
 pub struct ShredIndex {
-    /// Map representing presence/absence of shreds
-    index: BTreeSet<u64>,
+    /// Roaring-style bitmap of present shred indices.
+    ///
+    /// The index space is split on the high 48 bits of each value: every
+    /// distinct prefix owns one [`Container`] holding the low 16 bits, and the
+    /// containers are kept sorted by key so range counts can sum whole-container
+    /// cardinalities and only touch the two boundary containers directly.
+    containers: Vec<KeyedContainer>,
+}
+
+/// A [`Container`] tagged with the high-48-bit prefix it covers and the number
+/// of values it currently holds, so `present_in_bounds` can rank by summing
+/// cardinalities instead of walking the values.
+#[derive(Clone, Debug)]
+struct KeyedContainer {
+    key: u64,
+    cardinality: u32,
+    container: Container,
+}
+
+/// Per-prefix storage: a sorted array of low halves while the prefix is sparse,
+/// promoted to a flat bitset once it grows dense. The crossover is the point
+/// where the two representations cost the same (`ARRAY_MAX` × 2 bytes equals the
+/// 8 KiB bitset), so memory is always within a factor of two of optimal.
+#[derive(Clone, Debug)]
+enum Container {
+    Array(Vec<u16>),
+    Bitset(Box<[u64; BITSET_WORDS]>),
+}
+
+/// Number of 64-bit words needed to cover the 2^16 value slots in a container.
+const BITSET_WORDS: usize = 1 << 16 >> 6;
+/// Cardinality at which an array container is promoted to (or demoted from) a
+/// bitset container.
+const ARRAY_MAX: usize = 1 << 12;
+
+impl KeyedContainer {
+    fn new(key: u64) -> Self {
+        KeyedContainer {
+            key,
+            cardinality: 0,
+            container: Container::Array(Vec::new()),
+        }
+    }
+
+    fn insert(&mut self, low: u16) -> bool {
+        let inserted = self.container.insert(low);
+        if inserted {
+            self.cardinality += 1;
+            if matches!(self.container, Container::Array(ref v) if v.len() > ARRAY_MAX) {
+                self.container.to_bitset();
+            }
+        }
+        inserted
+    }
+
+    fn remove(&mut self, low: u16) -> bool {
+        let removed = self.container.remove(low);
+        if removed {
+            self.cardinality -= 1;
+            // Demote below the promotion threshold so a prefix hovering at the
+            // boundary doesn't reallocate on every insert/remove.
+            if matches!(self.container, Container::Bitset(_))
+                && (self.cardinality as usize) < ARRAY_MAX
+            {
+                self.container.to_array();
+            }
+        }
+        removed
+    }
+}
+
+impl Container {
+    fn insert(&mut self, low: u16) -> bool {
+        match self {
+            Container::Array(v) => match v.binary_search(&low) {
+                Ok(_) => false,
+                Err(pos) => {
+                    v.insert(pos, low);
+                    true
+                }
+            },
+            Container::Bitset(words) => {
+                let (word, mask) = bit_position(low);
+                let present = words[word] & mask != 0;
+                words[word] |= mask;
+                !present
+            }
+        }
+    }
+
+    fn remove(&mut self, low: u16) -> bool {
+        match self {
+            Container::Array(v) => match v.binary_search(&low) {
+                Ok(pos) => {
+                    v.remove(pos);
+                    true
+                }
+                Err(_) => false,
+            },
+            Container::Bitset(words) => {
+                let (word, mask) = bit_position(low);
+                let present = words[word] & mask != 0;
+                words[word] &= !mask;
+                present
+            }
+        }
+    }
+
+    fn contains(&self, low: u16) -> bool {
+        match self {
+            Container::Array(v) => v.binary_search(&low).is_ok(),
+            Container::Bitset(words) => {
+                let (word, mask) = bit_position(low);
+                words[word] & mask != 0
+            }
+        }
+    }
+
+    /// Count the values in the inclusive low-half range `lo..=hi`.
+    fn count_in_range(&self, lo: u16, hi: u16) -> usize {
+        match self {
+            Container::Array(v) => {
+                let start = v.partition_point(|&x| x < lo);
+                let end = v.partition_point(|&x| x <= hi);
+                end - start
+            }
+            Container::Bitset(words) => {
+                let (lo_word, _) = bit_position(lo);
+                let (hi_word, _) = bit_position(hi);
+                (lo_word..=hi_word)
+                    .map(|word| {
+                        let mut bits = words[word];
+                        if word == lo_word {
+                            bits &= u64::MAX << (lo & 63);
+                        }
+                        if word == hi_word {
+                            bits &= u64::MAX >> (63 - (hi & 63));
+                        }
+                        bits.count_ones() as usize
+                    })
+                    .sum()
+            }
+        }
+    }
+
+    fn max(&self) -> Option<u16> {
+        match self {
+            Container::Array(v) => v.last().copied(),
+            Container::Bitset(words) => words
+                .iter()
+                .enumerate()
+                .rev()
+                .find(|(_, &bits)| bits != 0)
+                .map(|(word, &bits)| (word as u16) << 6 | (63 - bits.leading_zeros() as u16)),
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = u16> + '_> {
+        match self {
+            Container::Array(v) => Box::new(v.iter().copied()),
+            Container::Bitset(words) => Box::new(words.iter().enumerate().flat_map(|(word, bits)| {
+                let base = (word as u16) << 6;
+                (0..64).filter_map(move |bit| (bits & (1u64 << bit) != 0).then_some(base | bit as u16))
+            })),
+        }
+    }
+
+    fn to_bitset(&mut self) {
+        if let Container::Array(v) = self {
+            let mut words = Box::new([0u64; BITSET_WORDS]);
+            for &low in v.iter() {
+                let (word, mask) = bit_position(low);
+                words[word] |= mask;
+            }
+            *self = Container::Bitset(words);
+        }
+    }
+
+    fn to_array(&mut self) {
+        if let Container::Bitset(_) = self {
+            let values: Vec<u16> = self.iter().collect();
+            *self = Container::Array(values);
+        }
+    }
+}
+
+/// Split a low half into its `(word, mask)` position inside a bitset container.
+fn bit_position(low: u16) -> (usize, u64) {
+    ((low >> 6) as usize, 1u64 << (low & 63))
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq)]
+/// Versioned erasure coding information.
+///
+/// Mirrors [`FrozenHashVersioned`]: the enum is what the blockstore persists,
+/// giving us a clean extension point for future erasure schemes (e.g. per-set
+/// variable data/coding ratios) behind a new variant.
+pub enum ErasureMetaVersioned {
+    Current(ErasureMetaV2),
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq)]
 /// Erasure coding information
-pub struct ErasureMeta {
+pub struct ErasureMetaV2 {
     /// Which erasure set in the slot this is
     set_index: u64,
-    /// First coding index in the FEC set
+    /// First coding index in the FEC set. Always populated in this version.
+    first_coding_index: u64,
+    /// Erasure configuration for this erasure set
+    config: ErasureConfig,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, Eq, PartialEq)]
+/// Legacy on-disk layout of `ErasureMeta`, kept only so existing records can
+/// be decoded and converted with [`ErasureMetaVersioned::from_legacy`].
+struct ErasureMetaLegacy {
+    set_index: u64,
     first_coding_index: u64,
-    /// Size of shards in this erasure set
     #[serde(rename = "size")]
     __unused_size: usize,
-    /// Erasure configuration for this erasure set
     config: ErasureConfig,
 }
 
@@ -136,22 +341,65 @@ impl Index {
 
 impl ShredIndex {
     pub fn num_shreds(&self) -> usize {
-        self.index.len()
+        self.containers
+            .iter()
+            .map(|c| c.cardinality as usize)
+            .sum()
     }
 
     pub fn present_in_bounds(&self, bounds: impl RangeBounds<u64>) -> usize {
-        self.index.range(bounds).count()
+        let (start, end) = match range_bounds(bounds) {
+            Some(range) => range,
+            None => return 0,
+        };
+        let (start_key, end_key) = (start >> 16, end >> 16);
+        // Containers are sorted by key, so a range count walks only the
+        // containers whose prefix falls inside [start_key, end_key]: the fully
+        // covered ones contribute their whole cardinality, and at most the two
+        // boundary containers need a partial count.
+        let first = self.containers.partition_point(|c| c.key < start_key);
+        self.containers[first..]
+            .iter()
+            .take_while(|c| c.key <= end_key)
+            .map(|c| {
+                let lo = if c.key == start_key { start as u16 } else { 0 };
+                let hi = if c.key == end_key { end as u16 } else { u16::MAX };
+                if (lo, hi) == (0, u16::MAX) {
+                    // Fully covered prefix: take the cardinality directly.
+                    c.cardinality as usize
+                } else {
+                    c.container.count_in_range(lo, hi)
+                }
+            })
+            .sum()
     }
 
     pub fn is_present(&self, index: u64) -> bool {
-        self.index.contains(&index)
+        let (key, low) = (index >> 16, index as u16);
+        self.containers
+            .binary_search_by_key(&key, |c| c.key)
+            .map_or(false, |pos| self.containers[pos].container.contains(low))
     }
 
     pub fn set_present(&mut self, index: u64, presence: bool) {
-        if presence {
-            self.index.insert(index);
-        } else {
-            self.index.remove(&index);
+        let (key, low) = (index >> 16, index as u16);
+        match self.containers.binary_search_by_key(&key, |c| c.key) {
+            Ok(pos) => {
+                if presence {
+                    self.containers[pos].insert(low);
+                } else if self.containers[pos].remove(low)
+                    && self.containers[pos].cardinality == 0
+                {
+                    // Drop containers that empty out so they don't linger.
+                    self.containers.remove(pos);
+                }
+            }
+            Err(pos) if presence => {
+                let mut container = KeyedContainer::new(key);
+                container.insert(low);
+                self.containers.insert(pos, container);
+            }
+            Err(_) => {}
         }
     }
 
@@ -162,7 +410,75 @@ impl ShredIndex {
     }
 
     pub fn largest(&self) -> Option<u64> {
-        self.index.iter().rev().next().copied()
+        let container = self.containers.last()?;
+        Some(container.key << 16 | u64::from(container.container.max()?))
+    }
+
+    /// Iterate the present indices in ascending order.
+    fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.containers
+            .iter()
+            .flat_map(|c| c.container.iter().map(move |low| c.key << 16 | u64::from(low)))
+    }
+}
+
+/// Normalize arbitrary [`RangeBounds`] into an inclusive `(start, end)` pair, or
+/// `None` when the range is empty.
+fn range_bounds(bounds: impl RangeBounds<u64>) -> Option<(u64, u64)> {
+    use std::ops::Bound::*;
+    let start = match bounds.start_bound() {
+        Included(&s) => s,
+        Excluded(&s) => s.checked_add(1)?,
+        Unbounded => u64::MIN,
+    };
+    let end = match bounds.end_bound() {
+        Included(&e) => e,
+        Excluded(&e) => e.checked_sub(1)?,
+        Unbounded => u64::MAX,
+    };
+    (start <= end).then_some((start, end))
+}
+
+impl PartialEq for ShredIndex {
+    fn eq(&self, other: &Self) -> bool {
+        // Two indices are equal when they hold the same logical set, regardless
+        // of whether any given prefix is stored as an array or a bitset.
+        self.num_shreds() == other.num_shreds() && self.iter().eq(other.iter())
+    }
+}
+
+impl Serialize for ShredIndex {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        // Emit the same logical set of u64 indices as the legacy
+        // `BTreeSet<u64>`-backed layout so existing blockstores stay readable.
+        #[derive(Serialize)]
+        struct Legacy {
+            index: BTreeSet<u64>,
+        }
+        Legacy {
+            index: self.iter().collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ShredIndex {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Legacy {
+            index: BTreeSet<u64>,
+        }
+        let mut shred_index = ShredIndex::default();
+        for idx in Legacy::deserialize(deserializer)?.index {
+            shred_index.set_present(idx, true);
+        }
+        Ok(shred_index)
     }
 }
 
@@ -227,7 +543,58 @@ impl SlotMeta {
     }
 }
 
-impl ErasureMeta {
+impl ErasureMetaVersioned {
+    pub(crate) fn from_coding_shred(shred: &Shred) -> Option<Self> {
+        ErasureMetaV2::from_coding_shred(shred).map(Self::Current)
+    }
+
+    /// Upgrade a legacy on-disk record to the current version.
+    ///
+    /// This converts an already decoded [`ErasureMetaLegacy`] value only: it
+    /// does not read the legacy layout from the blockstore, and it does not
+    /// write the upgraded record back.
+    pub(crate) fn from_legacy(legacy: ErasureMetaLegacy) -> Self {
+        Self::Current(ErasureMetaV2 {
+            set_index: legacy.set_index,
+            // Legacy records left first_coding_index at 0 to mean "unpopulated";
+            // coding shreds began at the set index under the old scheme.
+            first_coding_index: if legacy.first_coding_index == 0 {
+                legacy.set_index
+            } else {
+                legacy.first_coding_index
+            },
+            config: legacy.config,
+        })
+    }
+
+    fn current(&self) -> &ErasureMetaV2 {
+        match self {
+            ErasureMetaVersioned::Current(erasure_meta) => erasure_meta,
+        }
+    }
+
+    pub(crate) fn check_coding_shred(&self, shred: &Shred) -> bool {
+        self.current().check_coding_shred(shred)
+    }
+
+    pub(crate) fn config(&self) -> ErasureConfig {
+        self.current().config()
+    }
+
+    pub(crate) fn status(&self, index: &Index) -> ErasureMetaStatus {
+        self.current().status(index)
+    }
+
+    pub(crate) fn missing_data_indices(&self, index: &Index) -> Vec<u64> {
+        self.current().missing_data_indices(index)
+    }
+
+    pub(crate) fn missing_coding_indices(&self, index: &Index) -> Vec<u64> {
+        self.current().missing_coding_indices(index)
+    }
+}
+
+impl ErasureMetaV2 {
     pub(crate) fn from_coding_shred(shred: &Shred) -> Option<Self> {
         match shred.shred_type() {
             ShredType::Data => None,
@@ -237,11 +604,10 @@ impl ErasureMeta {
                     usize::from(shred.coding_header.num_coding_shreds),
                 );
                 let first_coding_index = u64::from(shred.first_coding_index()?);
-                let erasure_meta = ErasureMeta {
+                let erasure_meta = ErasureMetaV2 {
                     set_index: u64::from(shred.fec_set_index()),
                     config,
                     first_coding_index,
-                    __unused_size: 0,
                 };
                 Some(erasure_meta)
             }
@@ -251,16 +617,10 @@ impl ErasureMeta {
     // Returns true if the erasure fields on the shred
     // are consistent with the erasure-meta.
     pub(crate) fn check_coding_shred(&self, shred: &Shred) -> bool {
-        let mut other = match Self::from_coding_shred(shred) {
-            Some(erasure_meta) => erasure_meta,
-            None => return false,
-        };
-        other.__unused_size = self.__unused_size;
-        // Ignore first_coding_index field for now to be backward compatible.
-        // TODO remove this once cluster is upgraded to always populate
-        // first_coding_index field.
-        other.first_coding_index = self.first_coding_index;
-        self == &other
+        match Self::from_coding_shred(shred) {
+            Some(other) => self == &other,
+            None => false,
+        }
     }
 
     pub(crate) fn config(&self) -> ErasureConfig {
@@ -274,16 +634,27 @@ impl ErasureMeta {
 
     pub(crate) fn coding_shreds_indices(&self) -> Range<u64> {
         let num_coding = self.config.num_coding() as u64;
-        // first_coding_index == 0 may imply that the field is not populated.
-        // self.set_index to be backward compatible.
-        // TODO remove this once cluster is upgraded to always populate
-        // first_coding_index field.
-        let first_coding_index = if self.first_coding_index == 0 {
-            self.set_index
-        } else {
-            self.first_coding_index
-        };
-        first_coding_index..first_coding_index + num_coding
+        self.first_coding_index..self.first_coding_index + num_coding
+    }
+
+    // Returns the data shred indices of this erasure set that are not yet
+    // present in `index`. Once `status` is `DataFull` this is empty, and once
+    // it is `CanRecover` it is the minimal set of data shreds left to recover.
+    pub(crate) fn missing_data_indices(&self, index: &Index) -> Vec<u64> {
+        let data = index.data();
+        self.data_shreds_indices()
+            .filter(|i| !data.is_present(*i))
+            .collect()
+    }
+
+    // Returns the coding shred indices of this erasure set that are not yet
+    // present in `index`, e.g. for callers that would rather fetch enough
+    // coding shreds to trigger erasure recovery than repair data shreds.
+    pub(crate) fn missing_coding_indices(&self, index: &Index) -> Vec<u64> {
+        let coding = index.coding();
+        self.coding_shreds_indices()
+            .filter(|i| !coding.is_present(*i))
+            .collect()
     }
 
     pub(crate) fn status(&self, index: &Index) -> ErasureMetaStatus {
@@ -353,11 +724,10 @@ mod test {
         let set_index = 0;
         let erasure_config = ErasureConfig::new(8, 16);
 
-        let e_meta = ErasureMeta {
+        let e_meta = ErasureMetaV2 {
             set_index,
             first_coding_index: set_index,
             config: erasure_config,
-            __unused_size: 0,
         };
         let mut rng = thread_rng();
         let mut index = Index::new(0);
@@ -401,6 +771,82 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_erasure_meta_missing_indices() {
+        let set_index = 0;
+        let erasure_config = ErasureConfig::new(8, 16);
+        let e_meta = ErasureMetaV2 {
+            set_index,
+            first_coding_index: set_index,
+            config: erasure_config,
+        };
+        let mut index = Index::new(0);
+
+        let data_indexes = 0..erasure_config.num_data() as u64;
+        let coding_indexes = 0..erasure_config.num_coding() as u64;
+
+        // Nothing present yet: every index is missing.
+        assert_eq!(
+            e_meta.missing_data_indices(&index),
+            data_indexes.clone().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            e_meta.missing_coding_indices(&index),
+            coding_indexes.clone().collect::<Vec<_>>()
+        );
+
+        index
+            .data_mut()
+            .set_many_present(data_indexes.clone().zip(repeat(true)));
+        index
+            .coding_mut()
+            .set_many_present(coding_indexes.zip(repeat(true)));
+
+        // Fully populated: nothing missing.
+        assert!(e_meta.missing_data_indices(&index).is_empty());
+        assert!(e_meta.missing_coding_indices(&index).is_empty());
+
+        // Drop a couple of data shreds and confirm exactly those are reported.
+        index.data_mut().set_present(2, false);
+        index.data_mut().set_present(5, false);
+        assert_eq!(e_meta.missing_data_indices(&index), vec![2, 5]);
+        assert!(e_meta.missing_coding_indices(&index).is_empty());
+    }
+
+    #[test]
+    fn test_shred_index_presence_and_counts() {
+        let mut index = ShredIndex::default();
+        // Span three prefixes so range counts exercise the container boundaries.
+        let present = [0u64, 1, 7, 4096, 70_000, 70_001, 131_072];
+        index.set_many_present(present.iter().map(|&idx| (idx, true)));
+
+        assert_eq!(index.num_shreds(), present.len());
+        assert!(present.iter().all(|&idx| index.is_present(idx)));
+        assert!(!index.is_present(2));
+        assert_eq!(index.largest(), Some(131_072));
+
+        assert_eq!(index.present_in_bounds(..), present.len());
+        assert_eq!(index.present_in_bounds(0..4096), 3);
+        assert_eq!(index.present_in_bounds(4096..=70_000), 2);
+        assert_eq!(index.present_in_bounds(70_002..131_072), 0);
+    }
+
+    #[test]
+    fn test_shred_index_dense_promotion_and_removal() {
+        let mut index = ShredIndex::default();
+        // Fill a single prefix densely enough to promote to a bitset container.
+        let dense = 0..(ARRAY_MAX as u64 + 100);
+        index.set_many_present(dense.clone().zip(repeat(true)));
+        assert!(matches!(index.containers[0].container, Container::Bitset(_)));
+        assert_eq!(index.present_in_bounds(dense.clone()), ARRAY_MAX + 100);
+
+        // Clearing every index drops the now-empty container.
+        index.set_many_present(dense.map(|idx| (idx, false)));
+        assert_eq!(index.num_shreds(), 0);
+        assert!(index.containers.is_empty());
+        assert_eq!(index.largest(), None);
+    }
+
     #[test]
     fn test_clear_unconfirmed_slot() {
         let mut slot_meta = SlotMeta::new_orphan(5);